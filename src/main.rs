@@ -2,9 +2,89 @@ use clap::{App, Arg};
 use image::io::Reader;
 use image::{DynamicImage, GenericImageView, ImageBuffer, ImageError, Rgba};
 use png::{ColorType, Encoder};
-use std::io::{self, Read, Write};
+use std::io::{self, Read, Seek, Write};
 use std::str::FromStr;
 
+trait ImageSource {
+    fn content(&self) -> DynamicImage;
+}
+
+struct DecodeSource {
+    data: Vec<u8>,
+    verbose: bool,
+}
+
+impl ImageSource for DecodeSource {
+    fn content(&self) -> DynamicImage {
+        let reader = match Reader::new(std::io::Cursor::new(&self.data)).with_guessed_format() {
+            Ok(reader) => reader,
+            Err(e) => {
+                eprintln!("Failed to guess image format: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        if self.verbose {
+            eprintln!("Debug: Guessed image format: {:?}", reader.format());
+        }
+
+        let img = match reader.decode() {
+            Ok(img) => img,
+            Err(e) => {
+                match e {
+                    ImageError::IoError(io_err) => eprintln!("IO Error: {}", io_err),
+                    ImageError::Unsupported(msg) => eprintln!("Unsupported format: {}", msg),
+                    _ => eprintln!("Unknown error: {}", e),
+                }
+                std::process::exit(1);
+            }
+        };
+
+        if self.verbose {
+            eprintln!("Debug: Image successfully decoded");
+        }
+
+        img
+    }
+}
+
+struct CaptureSource {
+    display: usize,
+    region: Option<(i32, i32, u32, u32)>,
+}
+
+impl ImageSource for CaptureSource {
+    fn content(&self) -> DynamicImage {
+        let screens = screenshots::Screen::all().unwrap_or_else(|e| {
+            eprintln!("Failed to enumerate displays: {}", e);
+            std::process::exit(1);
+        });
+        let screen = screens.get(self.display).unwrap_or_else(|| {
+            eprintln!(
+                "Error: display {} not found ({} available)",
+                self.display,
+                screens.len()
+            );
+            std::process::exit(1);
+        });
+        let capture = screen.capture().unwrap_or_else(|e| {
+            eprintln!("Failed to capture display {}: {}", self.display, e);
+            std::process::exit(1);
+        });
+
+        let mut img = DynamicImage::ImageRgba8(
+            ImageBuffer::from_raw(capture.width(), capture.height(), capture.into_raw())
+                .expect("Captured frame buffer did not match its reported dimensions"),
+        );
+
+        if let Some((x, y, w, h)) = self.region {
+            img = img.crop(x.max(0) as u32, y.max(0) as u32, w, h);
+        }
+
+        img
+    }
+}
+
 fn main() {
     let matches = App::new("Image Rounder and Shadow Adder")
         .arg(
@@ -59,6 +139,84 @@ fn main() {
                 .short("v")
                 .help("Enable verbose output"),
         )
+        .arg(
+            Arg::with_name("blurhash")
+                .long("blurhash")
+                .help("Print a BlurHash placeholder string for the final image to stdout"),
+        )
+        .arg(
+            Arg::with_name("components")
+                .long("components")
+                .takes_value(true)
+                .default_value("4x3")
+                .help("BlurHash component count as WxH, each clamped to 1..=9"),
+        )
+        .arg(
+            Arg::with_name("capture")
+                .long("capture")
+                .help("Capture a live screenshot instead of reading a file or stdin"),
+        )
+        .arg(
+            Arg::with_name("display")
+                .long("display")
+                .takes_value(true)
+                .default_value("0")
+                .help("Display index to capture (with --capture)"),
+        )
+        .arg(
+            Arg::with_name("region")
+                .long("region")
+                .takes_value(true)
+                .help("Crop the captured frame to x,y,w,h (with --capture)"),
+        )
+        .arg(
+            Arg::with_name("background")
+                .long("background")
+                .takes_value(true)
+                .default_value("transparent")
+                .help("Backdrop: a hex color, \"transparent\", or \"gradient:#AABBCC,#112233[,angle]\""),
+        )
+        .arg(
+            Arg::with_name("padding")
+                .long("padding")
+                .takes_value(true)
+                .default_value("0")
+                .help("Padding between the shadowed image and the background edge"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .help("Output format: png, jpeg, webp, tiff (default: inferred from --output's extension, or png)"),
+        )
+        .arg(
+            Arg::with_name("quality")
+                .long("quality")
+                .takes_value(true)
+                .default_value("90")
+                .help("JPEG/WebP quality (0-100)"),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .takes_value(true)
+                .default_value("lzw")
+                .help("TIFF compression: lzw, deflate, packbits"),
+        )
+        .arg(
+            Arg::with_name("border_width")
+                .long("border-width")
+                .takes_value(true)
+                .default_value("0")
+                .help("Width of an anti-aliased stroke drawn around the rounded-rect outline"),
+        )
+        .arg(
+            Arg::with_name("border_color")
+                .long("border-color")
+                .takes_value(true)
+                .default_value("#000000")
+                .help("Color of the border stroke (hex, optionally with alpha)"),
+        )
         .get_matches();
 
     let verbose = matches.is_present("verbose");
@@ -67,6 +225,14 @@ fn main() {
         .value_of("corner_radius")
         .map(|s| u32::from_str(s).unwrap_or(0))
         .unwrap_or(0);
+    let border_width = matches
+        .value_of("border_width")
+        .map(|s| u32::from_str(s).unwrap_or(0))
+        .unwrap_or(0);
+    let border_color = matches
+        .value_of("border_color")
+        .map(parse_hex_color)
+        .unwrap_or(Rgba([0, 0, 0, 255]));
     let offset = matches
         .value_of("offset")
         .map(|s| {
@@ -89,62 +255,103 @@ fn main() {
         .value_of("spread")
         .map(|s| u32::from_str(s).unwrap_or(10))
         .unwrap_or(10);
-
-    let input_data = if let Some(input_path) = matches.value_of("input") {
-        std::fs::read(input_path).expect("Failed to read input file")
-    } else {
-        let mut buffer = Vec::new();
-        match io::stdin().read_to_end(&mut buffer) {
-            Ok(0) => {
-                eprintln!("Error: No input data received. Make sure you're piping an image to this program.");
-                std::process::exit(1);
-            }
-            Ok(n) => {
-                if verbose {
-                    eprintln!("Debug: Read {} bytes from stdin", n);
-                }
-                buffer
+    let blurhash = matches.is_present("blurhash");
+    let (x_comp, y_comp) = matches
+        .value_of("components")
+        .map(|s| {
+            let parts: Vec<&str> = s.split('x').collect();
+            if parts.len() == 2 {
+                (
+                    u32::from_str(parts[0]).unwrap_or(4),
+                    u32::from_str(parts[1]).unwrap_or(3),
+                )
+            } else {
+                (4, 3)
             }
-            Err(e) => {
-                eprintln!("Error reading from stdin: {}", e);
-                std::process::exit(1);
+        })
+        .unwrap_or((4, 3));
+    let x_comp = x_comp.clamp(1, 9);
+    let y_comp = y_comp.clamp(1, 9);
+    let background = matches
+        .value_of("background")
+        .map(parse_background)
+        .unwrap_or(Background::Transparent);
+    let padding = matches
+        .value_of("padding")
+        .map(|s| u32::from_str(s).unwrap_or(0))
+        .unwrap_or(0);
+    let format = matches
+        .value_of("format")
+        .and_then(|s| OutputFormat::from_str(s).ok());
+    let quality = matches
+        .value_of("quality")
+        .map(|s| u8::from_str(s).unwrap_or(90))
+        .unwrap_or(90);
+    let compression = matches
+        .value_of("compression")
+        .and_then(|s| TiffCompression::from_str(s).ok())
+        .unwrap_or(TiffCompression::Lzw);
+
+    let img = if matches.is_present("capture") {
+        let display = matches
+            .value_of("display")
+            .map(|s| usize::from_str(s).unwrap_or(0))
+            .unwrap_or(0);
+        let region = matches.value_of("region").map(|s| {
+            let parts: Vec<&str> = s.split(',').collect();
+            if parts.len() == 4 {
+                (
+                    i32::from_str(parts[0]).unwrap_or(0),
+                    i32::from_str(parts[1]).unwrap_or(0),
+                    u32::from_str(parts[2]).unwrap_or(0),
+                    u32::from_str(parts[3]).unwrap_or(0),
+                )
+            } else {
+                (0, 0, 0, 0)
             }
+        });
+
+        if verbose {
+            eprintln!("Debug: Capturing display {} region {:?}", display, region);
         }
-    };
 
-    if verbose {
-        eprintln!("Debug: Input data size: {} bytes", input_data.len());
-    }
+        CaptureSource { display, region }.content()
+    } else {
+        let input_data = if let Some(input_path) = matches.value_of("input") {
+            std::fs::read(input_path).expect("Failed to read input file")
+        } else {
+            let mut buffer = Vec::new();
+            match io::stdin().read_to_end(&mut buffer) {
+                Ok(0) => {
+                    eprintln!("Error: No input data received. Make sure you're piping an image to this program.");
+                    std::process::exit(1);
+                }
+                Ok(n) => {
+                    if verbose {
+                        eprintln!("Debug: Read {} bytes from stdin", n);
+                    }
+                    buffer
+                }
+                Err(e) => {
+                    eprintln!("Error reading from stdin: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        };
 
-    let img = match Reader::new(std::io::Cursor::new(&input_data)).with_guessed_format() {
-        Ok(reader) => reader,
-        Err(e) => {
-            eprintln!("Failed to guess image format: {}", e);
-            std::process::exit(1);
+        if verbose {
+            eprintln!("Debug: Input data size: {} bytes", input_data.len());
         }
-    };
-
-    if verbose {
-        eprintln!("Debug: Guessed image format: {:?}", img.format());
-    }
 
-    let img = match img.decode() {
-        Ok(img) => img,
-        Err(e) => {
-            match e {
-                ImageError::IoError(io_err) => eprintln!("IO Error: {}", io_err),
-                ImageError::Unsupported(msg) => eprintln!("Unsupported format: {}", msg),
-                _ => eprintln!("Unknown error: {}", e),
-            }
-            std::process::exit(1);
+        DecodeSource {
+            data: input_data,
+            verbose,
         }
+        .content()
     };
 
-    if verbose {
-        eprintln!("Debug: Image successfully decoded");
-    }
-
     let rounded_img = round_corners(&img, corner_radius);
+    let rounded_img = add_border(&rounded_img, corner_radius, border_width, border_color);
 
     let result = add_rounded_drop_shadow(&rounded_img, offset.0, offset.1, 5, spread, shadow_alpha)
         .unwrap_or_else(|e| {
@@ -152,30 +359,46 @@ fn main() {
             std::process::exit(1);
         });
 
+    let result = composite_on_background(&result, &background, padding);
+
+    if blurhash {
+        let hash = encode_blurhash(&result.to_rgba8(), x_comp, y_comp);
+        println!("{}", hash);
+    }
+
     if let Some(output_path) = matches.value_of("output") {
-        result
-            .save(output_path)
-            .expect("Failed to save output file");
+        let resolved_format = format
+            .or_else(|| format_from_extension(output_path))
+            .unwrap_or(OutputFormat::Png);
+        let mut file = std::fs::File::create(output_path).expect("Failed to create output file");
+        encode_image(
+            &result,
+            &background,
+            resolved_format,
+            quality,
+            compression,
+            &mut file,
+        )
+        .expect("Failed to encode output image");
         eprintln!(
             "Image with rounded corners and drop shadow saved as: {}",
             output_path
         );
     } else {
-        let rgba_image = result.to_rgba8();
-        let (width, height) = rgba_image.dimensions();
-        let mut png_data = Vec::new();
-        {
-            let mut encoder = Encoder::new(&mut png_data, width, height);
-            encoder.set_color(ColorType::Rgba);
-            encoder.set_depth(png::BitDepth::Eight);
-            let mut writer = encoder.write_header().expect("Failed to write PNG header");
-            writer
-                .write_image_data(rgba_image.as_raw())
-                .expect("Failed to write PNG data");
-        }
+        let resolved_format = format.unwrap_or(OutputFormat::Png);
+        let mut cursor = io::Cursor::new(Vec::new());
+        encode_image(
+            &result,
+            &background,
+            resolved_format,
+            quality,
+            compression,
+            &mut cursor,
+        )
+        .expect("Failed to encode output image");
         io::stdout()
             .lock()
-            .write_all(&png_data)
+            .write_all(&cursor.into_inner())
             .expect("Failed to write to stdout");
         io::stdout().flush().expect("Failed to flush stdout");
     }
@@ -265,6 +488,211 @@ fn round_corners(img: &DynamicImage, radius: u32) -> DynamicImage {
     DynamicImage::ImageRgba8(rounded)
 }
 
+fn alpha_over(base: Rgba<u8>, over: Rgba<u8>) -> Rgba<u8> {
+    let over_a = over[3] as f32 / 255.0;
+    let base_a = base[3] as f32 / 255.0;
+    let out_a = over_a + base_a * (1.0 - over_a);
+
+    if out_a <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+
+    let blend = |oc: u8, bc: u8| -> u8 {
+        ((oc as f32 * over_a + bc as f32 * base_a * (1.0 - over_a)) / out_a).round() as u8
+    };
+
+    Rgba([
+        blend(over[0], base[0]),
+        blend(over[1], base[1]),
+        blend(over[2], base[2]),
+        (out_a * 255.0).round() as u8,
+    ])
+}
+
+fn add_border(
+    img: &DynamicImage,
+    radius: u32,
+    border_width: u32,
+    border_color: Rgba<u8>,
+) -> DynamicImage {
+    let mut bordered = img.to_rgba8();
+    if border_width == 0 {
+        return DynamicImage::ImageRgba8(bordered);
+    }
+
+    let (width, height) = img.dimensions();
+    let radius = radius as f32;
+    let inner_radius = radius - border_width as f32;
+    // The stroke can be wider than the corner radius (e.g. `--radius 0
+    // --border-width 2` on a square image), so the straight-edge band has to
+    // be checked against whichever is wider, not just the corner radius.
+    let edge_band = radius.max(border_width as f32);
+
+    for (x, y, pixel) in img.to_rgba8().enumerate_pixels() {
+        let in_top = y < radius as u32;
+        let in_bottom = y >= height.saturating_sub(radius as u32);
+        let in_left = x < radius as u32;
+        let in_right = x >= width.saturating_sub(radius as u32);
+
+        let near_top = (y as f32) < edge_band;
+        let near_bottom = (y as f32) >= height as f32 - edge_band;
+        let near_left = (x as f32) < edge_band;
+        let near_right = (x as f32) >= width as f32 - edge_band;
+
+        let distance = if in_left && in_top {
+            let dx = radius - x as f32;
+            let dy = radius - y as f32;
+            (dx * dx + dy * dy).sqrt()
+        } else if in_right && in_top {
+            let dx = x as f32 - (width as f32 - radius - 1.0);
+            let dy = radius - y as f32;
+            (dx * dx + dy * dy).sqrt()
+        } else if in_left && in_bottom {
+            let dx = radius - x as f32;
+            let dy = y as f32 - (height as f32 - radius - 1.0);
+            (dx * dx + dy * dy).sqrt()
+        } else if in_right && in_bottom {
+            let dx = x as f32 - (width as f32 - radius - 1.0);
+            let dy = y as f32 - (height as f32 - radius - 1.0);
+            (dx * dx + dy * dy).sqrt()
+        } else if near_top {
+            radius - y as f32
+        } else if near_bottom {
+            radius - (height as f32 - 1.0 - y as f32)
+        } else if near_left {
+            radius - x as f32
+        } else if near_right {
+            radius - (width as f32 - 1.0 - x as f32)
+        } else {
+            continue;
+        };
+
+        if distance > radius + 1.0 || distance < inner_radius - 1.0 {
+            continue;
+        }
+
+        let outer_coverage = (radius + 1.0 - distance).clamp(0.0, 1.0);
+        let inner_coverage = (distance - (inner_radius - 1.0)).clamp(0.0, 1.0);
+        let coverage = outer_coverage.min(inner_coverage);
+        if coverage <= 0.0 {
+            continue;
+        }
+
+        let stroke_alpha = (border_color[3] as f32 * coverage).round() as u8;
+        let stroke = Rgba([
+            border_color[0],
+            border_color[1],
+            border_color[2],
+            stroke_alpha,
+        ]);
+        bordered.put_pixel(x, y, alpha_over(*pixel, stroke));
+    }
+
+    DynamicImage::ImageRgba8(bordered)
+}
+
+fn box_widths_for_sigma(sigma: f32) -> (u32, u32, u32) {
+    let ideal_width = (12.0 * sigma * sigma / 3.0 + 1.0).sqrt();
+    let mut wl = ideal_width.floor() as i32;
+    if wl % 2 == 0 {
+        wl -= 1;
+    }
+    let wl = wl.max(1) as u32;
+    let wu = wl + 2;
+
+    let wl_f = wl as f32;
+    let m = ((12.0 * sigma * sigma - 3.0 * wl_f * wl_f - 4.0 * wl_f - 1.0) / (-4.0 * wl_f - 4.0))
+        .round()
+        .max(0.0) as u32;
+
+    (wl, wu, m)
+}
+
+fn box_blur_h(src: &[f32], dst: &mut [f32], width: usize, height: usize, radius: i32) {
+    let r = radius as usize;
+    let window = (2 * r + 1) as f32;
+    for y in 0..height {
+        let row = y * width;
+        let first = src[row];
+        let last = src[row + width - 1];
+
+        let mut sum = first * (r as f32 + 1.0);
+        for x in 1..=r {
+            sum += src[row + x.min(width - 1)];
+        }
+
+        for x in 0..width {
+            dst[row + x] = sum / window;
+
+            let next_in = x + r + 1;
+            let incoming = if next_in < width {
+                src[row + next_in]
+            } else {
+                last
+            };
+
+            let next_out = x as i32 - r as i32;
+            let outgoing = if next_out >= 0 {
+                src[row + next_out as usize]
+            } else {
+                first
+            };
+
+            sum += incoming - outgoing;
+        }
+    }
+}
+
+fn box_blur_v(src: &[f32], dst: &mut [f32], width: usize, height: usize, radius: i32) {
+    let r = radius as usize;
+    let window = (2 * r + 1) as f32;
+    for x in 0..width {
+        let first = src[x];
+        let last = src[(height - 1) * width + x];
+
+        let mut sum = first * (r as f32 + 1.0);
+        for y in 1..=r {
+            sum += src[y.min(height - 1) * width + x];
+        }
+
+        for y in 0..height {
+            dst[y * width + x] = sum / window;
+
+            let next_in = y + r + 1;
+            let incoming = if next_in < height {
+                src[next_in * width + x]
+            } else {
+                last
+            };
+
+            let next_out = y as i32 - r as i32;
+            let outgoing = if next_out >= 0 {
+                src[next_out as usize * width + x]
+            } else {
+                first
+            };
+
+            sum += incoming - outgoing;
+        }
+    }
+}
+
+fn triple_box_blur(plane: &[f32], width: usize, height: usize, sigma: f32) -> Vec<f32> {
+    let (wl, wu, m) = box_widths_for_sigma(sigma);
+
+    let mut buf = plane.to_vec();
+    let mut tmp = vec![0.0f32; width * height];
+
+    for pass in 0..3 {
+        let box_width = if pass < m { wl } else { wu };
+        let radius = ((box_width - 1) / 2) as i32;
+        box_blur_h(&buf, &mut tmp, width, height, radius);
+        box_blur_v(&tmp, &mut buf, width, height, radius);
+    }
+
+    buf
+}
+
 fn create_shadow(
     img: &DynamicImage,
     blur_radius: u32,
@@ -291,7 +719,19 @@ fn create_shadow(
 
     let adjusted_blur_radius = blur_radius + (spread as f32 / 2.0) as u32;
 
-    let blurred = image::imageops::blur(&shadow, adjusted_blur_radius as f32);
+    let alpha_plane: Vec<f32> = shadow.pixels().map(|p| p[3] as f32).collect();
+    let blurred_alpha = triple_box_blur(
+        &alpha_plane,
+        new_width as usize,
+        new_height as usize,
+        adjusted_blur_radius as f32,
+    );
+
+    let blurred: ImageBuffer<Rgba<u8>, Vec<u8>> =
+        ImageBuffer::from_fn(new_width, new_height, |x, y| {
+            let alpha = blurred_alpha[(y * new_width + x) as usize];
+            Rgba([0, 0, 0, alpha.round().clamp(0.0, 255.0) as u8])
+        });
 
     let mut cleaned = ImageBuffer::new(new_width, new_height);
     for (x, y, pixel) in blurred.enumerate_pixels() {
@@ -312,3 +752,342 @@ fn create_shadow(
 
     DynamicImage::ImageRgba8(cleaned)
 }
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Tiff,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::WebP),
+            "tiff" | "tif" => Ok(OutputFormat::Tiff),
+            other => Err(format!("Unknown output format: {}", other)),
+        }
+    }
+}
+
+fn format_from_extension(path: &str) -> Option<OutputFormat> {
+    let ext = std::path::Path::new(path).extension()?.to_str()?;
+    OutputFormat::from_str(ext).ok()
+}
+
+#[derive(Clone, Copy)]
+enum TiffCompression {
+    Lzw,
+    Deflate,
+    PackBits,
+}
+
+impl FromStr for TiffCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lzw" => Ok(TiffCompression::Lzw),
+            "deflate" => Ok(TiffCompression::Deflate),
+            "packbits" => Ok(TiffCompression::PackBits),
+            other => Err(format!("Unknown TIFF compression: {}", other)),
+        }
+    }
+}
+
+fn flatten_for_jpeg(img: &DynamicImage, background: &Background) -> image::RgbImage {
+    // Unless the background is transparent, `img` has already been composited
+    // onto it by composite_on_background, so the alpha channel is dropped
+    // without rebuilding the backdrop or re-blending. A background color that
+    // carries its own alpha is not blended against anything; its alpha is
+    // simply dropped.
+    if !matches!(background, Background::Transparent) {
+        return img.to_rgb8();
+    }
+
+    let (width, height) = img.dimensions();
+    let backdrop = ImageBuffer::from_pixel(width, height, Rgba([255, 255, 255, 255]));
+
+    let rgba = img.to_rgba8();
+    let mut flattened = ImageBuffer::new(width, height);
+    for (x, y, pixel) in rgba.enumerate_pixels() {
+        let alpha = pixel[3] as f32 / 255.0;
+        let bg = backdrop.get_pixel(x, y);
+        let blend = |fg: u8, bg: u8| (fg as f32 * alpha + bg as f32 * (1.0 - alpha)).round() as u8;
+        flattened.put_pixel(
+            x,
+            y,
+            image::Rgb([
+                blend(pixel[0], bg[0]),
+                blend(pixel[1], bg[1]),
+                blend(pixel[2], bg[2]),
+            ]),
+        );
+    }
+    flattened
+}
+
+fn encode_image<W: Write + Seek>(
+    img: &DynamicImage,
+    background: &Background,
+    format: OutputFormat,
+    quality: u8,
+    compression: TiffCompression,
+    writer: &mut W,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Png => {
+            let rgba = img.to_rgba8();
+            let (width, height) = rgba.dimensions();
+            let mut encoder = Encoder::new(writer, width, height);
+            encoder.set_color(ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut png_writer = encoder.write_header()?;
+            png_writer.write_image_data(rgba.as_raw())?;
+        }
+        OutputFormat::Jpeg => {
+            let flattened = flatten_for_jpeg(img, background);
+            let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(writer, quality);
+            encoder.encode_image(&flattened)?;
+        }
+        OutputFormat::WebP => {
+            let rgba = img.to_rgba8();
+            let encoder = webp::Encoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height());
+            let encoded = if quality >= 100 {
+                encoder.encode_lossless()
+            } else {
+                encoder.encode(quality as f32)
+            };
+            writer.write_all(&encoded)?;
+        }
+        OutputFormat::Tiff => {
+            let rgba = img.to_rgba8();
+            let tiff_compression = match compression {
+                TiffCompression::Lzw => tiff::encoder::Compression::Lzw,
+                TiffCompression::Deflate => {
+                    tiff::encoder::Compression::Deflate(tiff::encoder::DeflateLevel::Balanced)
+                }
+                TiffCompression::PackBits => tiff::encoder::Compression::Packbits,
+            };
+            tiff::encoder::TiffEncoder::new(writer)?
+                .with_compression(tiff_compression)
+                .write_image::<tiff::encoder::colortype::RGBA8>(
+                    rgba.width(),
+                    rgba.height(),
+                    rgba.as_raw(),
+                )?;
+        }
+    }
+    Ok(())
+}
+
+enum Background {
+    Transparent,
+    Solid(Rgba<u8>),
+    Gradient(Rgba<u8>, Rgba<u8>, f32),
+}
+
+fn parse_background(s: &str) -> Background {
+    if s == "transparent" {
+        return Background::Transparent;
+    }
+    if let Some(rest) = s.strip_prefix("gradient:") {
+        let parts: Vec<&str> = rest.split(',').collect();
+        let start = parse_hex_color(parts.first().unwrap_or(&"#000000"));
+        let end = parse_hex_color(parts.get(1).unwrap_or(&"#000000"));
+        let angle = parts
+            .get(2)
+            .and_then(|s| f32::from_str(s).ok())
+            .unwrap_or(0.0);
+        return Background::Gradient(start, end, angle);
+    }
+    Background::Solid(parse_hex_color(s))
+}
+
+fn parse_hex_color(s: &str) -> Rgba<u8> {
+    let s = s.trim_start_matches('#');
+    let (r, g, b, a) = match s.len() {
+        6 => (
+            u8::from_str_radix(&s[0..2], 16).unwrap_or(0),
+            u8::from_str_radix(&s[2..4], 16).unwrap_or(0),
+            u8::from_str_radix(&s[4..6], 16).unwrap_or(0),
+            255,
+        ),
+        8 => (
+            u8::from_str_radix(&s[0..2], 16).unwrap_or(0),
+            u8::from_str_radix(&s[2..4], 16).unwrap_or(0),
+            u8::from_str_radix(&s[4..6], 16).unwrap_or(0),
+            u8::from_str_radix(&s[6..8], 16).unwrap_or(255),
+        ),
+        _ => (0, 0, 0, 255),
+    };
+    Rgba([r, g, b, a])
+}
+
+fn lerp_channel(a: u8, b: u8, t: f32) -> u8 {
+    (a as f32 * (1.0 - t) + b as f32 * t).round() as u8
+}
+
+fn gradient_buffer(
+    width: u32,
+    height: u32,
+    start: Rgba<u8>,
+    end: Rgba<u8>,
+    angle_degrees: f32,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let theta = angle_degrees.to_radians();
+    let (sin_t, cos_t) = theta.sin_cos();
+
+    let corners = [
+        (0.0, 0.0),
+        (width as f32, 0.0),
+        (0.0, height as f32),
+        (width as f32, height as f32),
+    ];
+    let projections: Vec<f32> = corners.iter().map(|(x, y)| x * cos_t + y * sin_t).collect();
+    let min = projections.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = projections
+        .iter()
+        .cloned()
+        .fold(f32::NEG_INFINITY, f32::max);
+    let range = if (max - min).abs() < f32::EPSILON {
+        1.0
+    } else {
+        max - min
+    };
+
+    let mut buffer = ImageBuffer::new(width, height);
+    for (x, y, pixel) in buffer.enumerate_pixels_mut() {
+        let projection = x as f32 * cos_t + y as f32 * sin_t;
+        let t = ((projection - min) / range).clamp(0.0, 1.0);
+        *pixel = Rgba([
+            lerp_channel(start[0], end[0], t),
+            lerp_channel(start[1], end[1], t),
+            lerp_channel(start[2], end[2], t),
+            lerp_channel(start[3], end[3], t),
+        ]);
+    }
+    buffer
+}
+
+fn composite_on_background(
+    img: &DynamicImage,
+    background: &Background,
+    padding: u32,
+) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let total_width = width + padding * 2;
+    let total_height = height + padding * 2;
+
+    let mut canvas: ImageBuffer<Rgba<u8>, Vec<u8>> = match background {
+        Background::Transparent => ImageBuffer::new(total_width, total_height),
+        Background::Solid(color) => ImageBuffer::from_pixel(total_width, total_height, *color),
+        Background::Gradient(start, end, angle) => {
+            gradient_buffer(total_width, total_height, *start, *end, *angle)
+        }
+    };
+
+    image::imageops::overlay(&mut canvas, &img.to_rgba8(), padding as i64, padding as i64);
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+const BASE83_CHARS: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let v = value as f32 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let v = value.clamp(0.0, 1.0);
+    let srgb = if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+fn base83_encode(mut value: u32, length: usize) -> String {
+    let mut result = vec![0u8; length];
+    for i in (0..length).rev() {
+        result[i] = BASE83_CHARS[(value % 83) as usize];
+        value /= 83;
+    }
+    String::from_utf8(result).unwrap()
+}
+
+fn encode_blurhash(img: &image::RgbaImage, x_comp: u32, y_comp: u32) -> String {
+    let (width, height) = img.dimensions();
+    let w = width as f32;
+    let h = height as f32;
+
+    let mut factors = Vec::with_capacity((x_comp * y_comp) as usize);
+    for j in 0..y_comp {
+        for i in 0..x_comp {
+            let normalization = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut r = 0.0f32;
+            let mut g = 0.0f32;
+            let mut b = 0.0f32;
+            for (px, py, pixel) in img.enumerate_pixels() {
+                let basis = (std::f32::consts::PI * i as f32 * px as f32 / w).cos()
+                    * (std::f32::consts::PI * j as f32 * py as f32 / h).cos();
+                r += basis * srgb_to_linear(pixel[0]);
+                g += basis * srgb_to_linear(pixel[1]);
+                b += basis * srgb_to_linear(pixel[2]);
+            }
+            let scale = normalization / (w * h);
+            factors.push((r * scale, g * scale, b * scale));
+        }
+    }
+
+    let dc = factors[0];
+    let ac = &factors[1..];
+
+    let mut hash = String::new();
+    hash.push_str(&base83_encode((x_comp - 1) + (y_comp - 1) * 9, 1));
+
+    let max_ac = if ac.is_empty() {
+        0.0
+    } else {
+        ac.iter()
+            .flat_map(|(r, g, b)| [r.abs(), g.abs(), b.abs()])
+            .fold(0.0f32, f32::max)
+    };
+
+    let quantized_max_ac = if max_ac > 0.0 {
+        ((max_ac * 166.0 - 0.5).floor().max(0.0) as u32).min(82)
+    } else {
+        0
+    };
+    hash.push_str(&base83_encode(quantized_max_ac, 1));
+
+    let dc_value = ((linear_to_srgb(dc.0) as u32) << 16)
+        | ((linear_to_srgb(dc.1) as u32) << 8)
+        | (linear_to_srgb(dc.2) as u32);
+    hash.push_str(&base83_encode(dc_value, 4));
+
+    let actual_max_ac = (quantized_max_ac + 1) as f32 / 166.0;
+    let quantize = |v: f32| -> i32 {
+        let normalized = v / actual_max_ac;
+        let sign_pow = normalized.signum() * normalized.abs().powf(0.5);
+        (sign_pow * 9.0 + 9.5).floor().clamp(0.0, 18.0) as i32
+    };
+    for &(r, g, b) in ac {
+        let value = (quantize(r) * 19 * 19 + quantize(g) * 19 + quantize(b)) as u32;
+        hash.push_str(&base83_encode(value, 2));
+    }
+
+    hash
+}